@@ -1,20 +1,31 @@
 // Copyright 2021 the Deno authors. All rights reserved. MIT license.
 
 use deno_core::error::bad_resource_id;
+use deno_core::error::type_error;
 use deno_core::error::AnyError;
 use deno_core::include_js_files;
+use deno_core::op_async;
 use deno_core::op_sync;
-use deno_core::serde_json::json;
 use deno_core::serde_json::Value;
+use deno_core::serde_v8;
+use deno_core::v8;
 use deno_core::Extension;
 use deno_core::OpState;
 use deno_core::Resource;
 use deno_core::ResourceId;
 use dlopen::raw::Library;
+use libffi::middle::Arg;
 use libffi::middle::Cif;
+use libffi::middle::CodePtr;
 use serde::Deserialize;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::c_void;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_char;
 use std::path::Path;
 use std::rc::Rc;
 
@@ -49,7 +60,24 @@ impl FfiPermissions for NoFfiPermissions {
   }
 }
 
-struct DylibResource(Library);
+/// A symbol resolved and type-checked once at `Deno.dlopen()` time, so that
+/// calling it only has to look up this entry and supply arguments, rather
+/// than re-resolving the symbol and rebuilding its `Cif` on every call.
+/// Non-blocking calls reuse the resolved `ptr` and the type lists but build
+/// a fresh `Cif` from them on every call.
+struct Symbol {
+  cif: Cif,
+  ptr: CodePtr,
+  parameter_types: Vec<FFIType>,
+  result_type: FFIType,
+  nonblocking: bool,
+}
+
+struct DylibResource {
+  // Kept alive for as long as `symbols` holds pointers into it.
+  _lib: Library,
+  symbols: HashMap<String, Box<Symbol>>,
+}
 
 impl Resource for DylibResource {
   fn name(&self) -> Cow<str> {
@@ -70,6 +98,18 @@ pub fn init<P: FfiPermissions + 'static>(unstable: bool) -> Extension {
     .ops(vec![
       ("op_dlopen", op_sync(op_dlopen::<P>)),
       ("op_dlcall", op_sync(op_dlcall::<P>)),
+      (
+        "op_dlcall_nonblocking",
+        op_async(op_dlcall_nonblocking::<P>),
+      ),
+      (
+        "op_ffi_unsafe_callback_create",
+        op_sync(op_ffi_unsafe_callback_create::<P>),
+      ),
+      (
+        "op_ffi_unsafe_callback_ref",
+        op_sync(op_ffi_unsafe_callback_ref),
+      ),
     ])
     .state(move |state| {
       // Stolen from deno_webgpu, is there a better option?
@@ -79,9 +119,76 @@ pub fn init<P: FfiPermissions + 'static>(unstable: bool) -> Extension {
     .build()
 }
 
+/// The parameter/result signature of a JS function usable as a native
+/// callback (e.g. a `qsort` comparator), as declared in a `{ callback: ... }`
+/// `FFITypeDef`.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CallbackSignature {
+  parameters: Vec<FFITypeDef>,
+  result: Box<FFITypeDef>,
+}
+
+/// A parameter or result type as written in a `Deno.dlopen()` symbol
+/// definition: either a scalar type name (`"u32"`, `"pointer"`, ...), a
+/// `{ struct: [...] }` descriptor for a by-value struct (whose fields are
+/// themselves `FFITypeDef`s so structs can nest), or a `{ callback: {...} }`
+/// descriptor for a parameter that accepts a JS function as a native
+/// function pointer.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum FFITypeDef {
+  Scalar(String),
+  Struct { r#struct: Vec<FFITypeDef> },
+  Callback { callback: CallbackSignature },
+}
+
+impl TryFrom<FFITypeDef> for FFIType {
+  type Error = AnyError;
+
+  fn try_from(def: FFITypeDef) -> Result<Self, AnyError> {
+    Ok(match def {
+      FFITypeDef::Scalar(scalar) => FFIType::try_from(scalar)?,
+      FFITypeDef::Struct { r#struct } => FFIType::Struct(
+        r#struct
+          .into_iter()
+          .map(FFIType::try_from)
+          .collect::<Result<_, AnyError>>()?,
+      ),
+      FFITypeDef::Callback { callback } => FFIType::Callback(
+        callback
+          .parameters
+          .into_iter()
+          .map(FFIType::try_from)
+          .collect::<Result<_, AnyError>>()?,
+        Box::new(FFIType::try_from(*callback.result)?),
+      ),
+    })
+  }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ForeignFunction {
+  parameters: Vec<FFITypeDef>,
+  result: FFITypeDef,
+  /// When set, `Deno.dlcall()` for this symbol must go through
+  /// `op_dlcall_nonblocking` instead, which runs the native call on the
+  /// blocking thread pool so it doesn't stall the event loop.
+  #[serde(default)]
+  nonblocking: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DlopenArgs {
+  path: String,
+  symbols: HashMap<String, ForeignFunction>,
+}
+
 fn op_dlopen<FP>(
   state: &mut deno_core::OpState,
-  path: String,
+  args: DlopenArgs,
   _: (),
 ) -> Result<ResourceId, AnyError>
 where
@@ -90,66 +197,239 @@ where
   check_unstable(state, "Deno.dlopen");
   let permissions = state.borrow_mut::<FP>();
   permissions.check()?;
-  permissions.check_read(Path::new(&path))?;
+  permissions.check_read(Path::new(&args.path))?;
 
-  Ok(
-    state
-      .resource_table
-      .add(DylibResource(Library::open(path)?)),
-  )
+  let lib = Library::open(&args.path)?;
+  let mut symbols = HashMap::with_capacity(args.symbols.len());
+  for (name, foreign_fn) in args.symbols {
+    let fn_ptr = unsafe { lib.symbol::<*const c_void>(&name) }?;
+    let ptr = CodePtr::from_ptr(fn_ptr as _);
+    let parameter_types: Vec<FFIType> = foreign_fn
+      .parameters
+      .into_iter()
+      .map(FFIType::try_from)
+      .collect::<Result<_, AnyError>>()?;
+    let result_type = FFIType::try_from(foreign_fn.result)?;
+    let cif = Cif::new(
+      parameter_types.iter().cloned().map(Into::into),
+      result_type.clone().into(),
+    );
+    symbols.insert(
+      name,
+      Box::new(Symbol {
+        cif,
+        ptr,
+        parameter_types,
+        result_type,
+        nonblocking: foreign_fn.nonblocking,
+      }),
+    );
+  }
+
+  Ok(state.resource_table.add(DylibResource {
+    _lib: lib,
+    symbols,
+  }))
 }
 
-#[derive(Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct FFIArg {
-  arg_type: String,
-  value: Value,
-}
-
-impl From<FFIArg> for libffi::middle::Arg {
-  fn from(arg: FFIArg) -> Self {
-    match arg.arg_type.clone().into() {
-      FFIType::Void => libffi::middle::Arg::new(&()),
-      FFIType::U8 => libffi::middle::Arg::new(&(arg.as_u64() as u8)),
-      FFIType::I8 => libffi::middle::Arg::new(&(arg.as_i64() as i8)),
-      FFIType::U16 => libffi::middle::Arg::new(&(arg.as_u64() as u16)),
-      FFIType::I16 => libffi::middle::Arg::new(&(arg.as_i64() as i16)),
-      FFIType::U32 => libffi::middle::Arg::new(&(arg.as_u64() as u32)),
-      FFIType::I32 => libffi::middle::Arg::new(&(arg.as_i64() as i32)),
-      FFIType::U64 => libffi::middle::Arg::new(&arg.as_u64()),
-      FFIType::I64 => libffi::middle::Arg::new(&arg.as_i64()),
-      FFIType::USize => libffi::middle::Arg::new(&(arg.as_u64() as usize)),
-      FFIType::ISize => libffi::middle::Arg::new(&(arg.as_i64() as isize)),
-      FFIType::F32 => libffi::middle::Arg::new(&(arg.as_f64() as f32)),
-      FFIType::F64 => libffi::middle::Arg::new(&arg.as_f64()),
+/// Owned storage that a [`Arg`] may point into. `ffi_arg` hands this back
+/// alongside the `Arg` so the caller can keep it alive for exactly as long
+/// as the native call needs it.
+///
+/// Scalars are boxed rather than stored inline: `Arg::new` captures the
+/// address of whatever it's given, and `ArgBacking` itself gets moved
+/// (e.g. out of `ffi_arg`, then into a `Vec`) before that `Arg` is ever
+/// used. A `Box<T>`'s pointee lives on the heap, so moving the `Box`
+/// around only copies the pointer — the address `Arg` captured stays
+/// valid. Storing the scalar inline would let the move relocate it out
+/// from under the `Arg`, which is exactly the stack-use-after-move bug
+/// this enum exists to avoid.
+enum ArgBacking {
+  None,
+  U8(Box<u8>),
+  I8(Box<i8>),
+  U16(Box<u16>),
+  I16(Box<i16>),
+  U32(Box<u32>),
+  I32(Box<i32>),
+  U64(Box<u64>),
+  I64(Box<i64>),
+  USize(Box<usize>),
+  ISize(Box<isize>),
+  F32(Box<f32>),
+  F64(Box<f64>),
+  Pointer(Box<*const c_void>),
+  Bytes(Box<[u8]>),
+  CString(CString, Box<*const c_char>),
+}
+
+fn ffi_arg(r#type: FFIType, value: &Value) -> Result<(ArgBacking, Arg), AnyError> {
+  Ok(match r#type {
+    FFIType::Void => (ArgBacking::None, Arg::new(&())),
+    FFIType::U8 => {
+      let backing = Box::new(value_as_u64(value)? as u8);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::U8(backing), arg)
     }
-  }
+    FFIType::I8 => {
+      let backing = Box::new(value_as_i64(value)? as i8);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::I8(backing), arg)
+    }
+    FFIType::U16 => {
+      let backing = Box::new(value_as_u64(value)? as u16);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::U16(backing), arg)
+    }
+    FFIType::I16 => {
+      let backing = Box::new(value_as_i64(value)? as i16);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::I16(backing), arg)
+    }
+    FFIType::U32 => {
+      let backing = Box::new(value_as_u64(value)? as u32);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::U32(backing), arg)
+    }
+    FFIType::I32 => {
+      let backing = Box::new(value_as_i64(value)? as i32);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::I32(backing), arg)
+    }
+    FFIType::U64 => {
+      let backing = Box::new(value_as_u64(value)?);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::U64(backing), arg)
+    }
+    FFIType::I64 => {
+      let backing = Box::new(value_as_i64(value)?);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::I64(backing), arg)
+    }
+    FFIType::USize => {
+      let backing = Box::new(value_as_u64(value)? as usize);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::USize(backing), arg)
+    }
+    FFIType::ISize => {
+      let backing = Box::new(value_as_i64(value)? as isize);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::ISize(backing), arg)
+    }
+    FFIType::F32 => {
+      let backing = Box::new(value_as_f64(value)? as f32);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::F32(backing), arg)
+    }
+    FFIType::F64 => {
+      let backing = Box::new(value_as_f64(value)?);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::F64(backing), arg)
+    }
+    // A bare pointer value: JS hands us the address as a number/BigInt.
+    FFIType::Pointer => {
+      let backing = Box::new(value_as_u64(value)? as *const c_void);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::Pointer(backing), arg)
+    }
+    // An `ArrayBuffer`/`TypedArray` argument, handed over the same way as
+    // `Pointer`: the caller resolves its own backing address (e.g. via
+    // `Deno.UnsafePointer.of()`) and passes that address across, rather
+    // than this op copying the buffer's contents into a fresh JSON array
+    // and back out again. That copy was the opposite of what the original
+    // `Buffer` type was supposed to get us.
+    FFIType::Buffer => {
+      let backing = Box::new(value_as_u64(value)? as *const c_void);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::Pointer(backing), arg)
+    }
+    // A JS string, marshalled to a NUL-terminated buffer that outlives the
+    // call via `ArgBacking::CString`. Rejected with a catchable error,
+    // rather than a panic, if it contains an embedded NUL byte, since that
+    // can't be represented as a C string at all.
+    FFIType::CStr => {
+      let cstring = CString::new(value_as_str(value)?).map_err(|_| {
+        type_error("Expected ffi arg value to be a C string without embedded NUL bytes")
+      })?;
+      let ptr = Box::new(cstring.as_ptr());
+      let arg = Arg::new(&*ptr);
+      (ArgBacking::CString(cstring, ptr), arg)
+    }
+    // A by-value struct IS its bytes, unlike `Buffer` which passes a
+    // pointer to its bytes: libffi reads `size_of::<struct>()` bytes
+    // straight from the `Arg`'s address, so the byte array must be
+    // exactly that long — too few and `&bytes[0]` doesn't even cover the
+    // struct, too many and callers could be masking a marshalling bug.
+    FFIType::Struct(ref fields) => {
+      let bytes = value_as_bytes(value)?.into_boxed_slice();
+      let size = ffi_type_size(fields);
+      if bytes.len() != size {
+        return Err(type_error(format!(
+          "Expected ffi struct arg to be exactly {} bytes, got {}",
+          size,
+          bytes.len()
+        )));
+      }
+      // A zero-field struct passes the length check above with an empty
+      // `bytes`, but `&bytes[0]` would index out of bounds on it — there's
+      // nothing for libffi to read, so hand it a zero-size unit arg instead
+      // of indexing into the (empty) backing at all.
+      let arg = if bytes.is_empty() {
+        Arg::new(&())
+      } else {
+        Arg::new(&bytes[0])
+      };
+      (ArgBacking::Bytes(bytes), arg)
+    }
+    // The code pointer of a trampoline previously created via
+    // `Deno.UnsafeCallback`, handed over the same way as `Pointer`.
+    FFIType::Callback(_, _) => {
+      let backing = Box::new(value_as_u64(value)? as *const c_void);
+      let arg = Arg::new(&*backing);
+      (ArgBacking::Pointer(backing), arg)
+    }
+  })
 }
 
-impl FFIArg {
-  fn as_u64(&self) -> u64 {
-    self
-      .value
-      .as_u64()
-      .expect("Expected ffi arg value to be an unsigned integer")
-  }
+fn value_as_u64(value: &Value) -> Result<u64, AnyError> {
+  value
+    .as_u64()
+    .ok_or_else(|| type_error("Expected ffi arg value to be an unsigned integer"))
+}
 
-  fn as_i64(&self) -> i64 {
-    self
-      .value
-      .as_i64()
-      .expect("Expected ffi arg value to be a signed integer")
-  }
+fn value_as_i64(value: &Value) -> Result<i64, AnyError> {
+  value
+    .as_i64()
+    .ok_or_else(|| type_error("Expected ffi arg value to be a signed integer"))
+}
 
-  fn as_f64(&self) -> f64 {
-    self
-      .value
-      .as_f64()
-      .expect("Expected ffi arg value to be a float")
-  }
+fn value_as_f64(value: &Value) -> Result<f64, AnyError> {
+  value
+    .as_f64()
+    .ok_or_else(|| type_error("Expected ffi arg value to be a float"))
+}
+
+fn value_as_bytes(value: &Value) -> Result<Vec<u8>, AnyError> {
+  value
+    .as_array()
+    .ok_or_else(|| type_error("Expected ffi arg value to be a byte array"))?
+    .iter()
+    .map(|byte| {
+      byte
+        .as_u64()
+        .map(|byte| byte as u8)
+        .ok_or_else(|| type_error("Expected ffi arg byte to be a number"))
+    })
+    .collect()
+}
+
+fn value_as_str(value: &Value) -> Result<&str, AnyError> {
+  value
+    .as_str()
+    .ok_or_else(|| type_error("Expected ffi arg value to be a string"))
 }
 
-#[derive(Deserialize, Clone, Copy)]
+#[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 enum FFIType {
   Void,
@@ -165,9 +445,11 @@ enum FFIType {
   ISize,
   F32,
   F64,
-  //  Ptr,
-  //  CStr,
-  //  Struct(Vec<FFIType>),
+  Pointer,
+  Buffer,
+  CStr,
+  Struct(Vec<FFIType>),
+  Callback(Vec<FFIType>, Box<FFIType>),
 }
 
 impl From<FFIType> for libffi::middle::Type {
@@ -186,13 +468,39 @@ impl From<FFIType> for libffi::middle::Type {
       FFIType::ISize => libffi::middle::Type::isize(),
       FFIType::F32 => libffi::middle::Type::f32(),
       FFIType::F64 => libffi::middle::Type::f64(),
+      FFIType::Pointer | FFIType::Buffer | FFIType::CStr => {
+        libffi::middle::Type::pointer()
+      }
+      // A JS callback is handed to native code as a trampoline's code
+      // pointer, same representation as `Pointer`.
+      FFIType::Callback(_, _) => libffi::middle::Type::pointer(),
+      FFIType::Struct(fields) => {
+        libffi::middle::Type::structure(fields.into_iter().map(Into::into))
+      }
     }
   }
 }
 
-impl From<String> for FFIType {
-  fn from(string: String) -> Self {
-    match string.as_str() {
+/// The size in bytes of a by-value struct made up of `fields`, i.e. what
+/// libffi will read from (or write into) a struct `Arg`'s address. Used to
+/// validate caller-supplied struct byte buffers before handing their
+/// address to libffi, since libffi itself has no way to report a
+/// size mismatch — it just reads or writes `size` bytes regardless.
+fn ffi_type_size(fields: &[FFIType]) -> usize {
+  // libffi only fills in a struct type's size when a `Cif` using it is
+  // prepared, so read it back from a throwaway `Cif` returning the struct.
+  let cif = Cif::new(
+    std::iter::empty(),
+    FFIType::Struct(fields.to_vec()).into(),
+  );
+  unsafe { (*(*cif.as_raw_ptr()).rtype).size as usize }
+}
+
+impl TryFrom<String> for FFIType {
+  type Error = AnyError;
+
+  fn try_from(string: String) -> Result<Self, AnyError> {
+    Ok(match string.as_str() {
       "void" => FFIType::Void,
       "u8" => FFIType::U8,
       "i8" => FFIType::I8,
@@ -206,24 +514,35 @@ impl From<String> for FFIType {
       "isize" => FFIType::ISize,
       "f32" => FFIType::F32,
       "f64" => FFIType::F64,
-      _ => unimplemented!(),
-    }
+      "pointer" => FFIType::Pointer,
+      "buffer" => FFIType::Buffer,
+      "cstr" => FFIType::CStr,
+      _ => {
+        return Err(type_error(format!(
+          "Unsupported FFI type name: {}",
+          string
+        )))
+      }
+    })
   }
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct DlcallArgs {
-  sym: String,
-  args: Vec<FFIArg>,
-  return_type: String,
+  symbol: String,
+  parameters: Vec<Value>,
+  /// Pre-allocated backing storage for a by-value struct return, sized to
+  /// the struct's byte size. If omitted, a buffer is allocated per call.
+  #[serde(default)]
+  out_buffer: Option<Vec<u8>>,
 }
 
 fn op_dlcall<FP>(
   state: &mut deno_core::OpState,
   rid: ResourceId,
   dlcall_args: DlcallArgs,
-) -> Result<Value, AnyError>
+) -> Result<serde_v8::Value, AnyError>
 where
   FP: FfiPermissions + 'static,
 {
@@ -231,35 +550,518 @@ where
   let permissions = state.borrow_mut::<FP>();
   permissions.check()?;
 
-  let library = state
+  let resource = state
     .resource_table
     .get::<DylibResource>(rid)
     .ok_or_else(bad_resource_id)?;
-  let fn_ptr = unsafe { library.0.symbol::<*const c_void>(&dlcall_args.sym) }?;
-  let fn_code_ptr = libffi::middle::CodePtr::from_ptr(fn_ptr as _);
-  let types = dlcall_args
-    .args
-    .clone()
+  let symbol = resource.symbols.get(&dlcall_args.symbol).ok_or_else(|| {
+    type_error(format!("Invalid FFI symbol name: {}", dlcall_args.symbol))
+  })?;
+
+  let (_backings, args): (Vec<ArgBacking>, Vec<Arg>) = symbol
+    .parameter_types
+    .iter()
+    .cloned()
+    .zip(dlcall_args.parameters.iter())
+    .map(|(r#type, value)| ffi_arg(r#type, value))
+    .collect::<Result<Vec<_>, AnyError>>()?
     .into_iter()
-    .map(|arg| FFIType::from(arg.arg_type).into());
-  let return_type = FFIType::from(dlcall_args.return_type);
-  let cif = Cif::new(types, return_type.into());
-  let args: Vec<libffi::middle::Arg> =
-    dlcall_args.args.into_iter().map(|arg| arg.into()).collect();
-
-  Ok(match return_type {
-    FFIType::Void => json!(unsafe { cif.call::<()>(fn_code_ptr, &args) }),
-    FFIType::U8 => json!(unsafe { cif.call::<u8>(fn_code_ptr, &args) }),
-    FFIType::I8 => json!(unsafe { cif.call::<i8>(fn_code_ptr, &args) }),
-    FFIType::U16 => json!(unsafe { cif.call::<u16>(fn_code_ptr, &args) }),
-    FFIType::I16 => json!(unsafe { cif.call::<i16>(fn_code_ptr, &args) }),
-    FFIType::U32 => json!(unsafe { cif.call::<u32>(fn_code_ptr, &args) }),
-    FFIType::I32 => json!(unsafe { cif.call::<i32>(fn_code_ptr, &args) }),
-    FFIType::U64 => json!(unsafe { cif.call::<u64>(fn_code_ptr, &args) }),
-    FFIType::I64 => json!(unsafe { cif.call::<i64>(fn_code_ptr, &args) }),
-    FFIType::USize => json!(unsafe { cif.call::<usize>(fn_code_ptr, &args) }),
-    FFIType::ISize => json!(unsafe { cif.call::<isize>(fn_code_ptr, &args) }),
-    FFIType::F32 => json!(unsafe { cif.call::<f32>(fn_code_ptr, &args) }),
-    FFIType::F64 => json!(unsafe { cif.call::<f64>(fn_code_ptr, &args) }),
+    .unzip();
+
+  let result = call_symbol(
+    &symbol.cif,
+    symbol.ptr,
+    &args,
+    &symbol.result_type,
+    dlcall_args.out_buffer,
+  )?;
+
+  let isolate_ptr = *state.borrow::<*mut v8::Isolate>();
+  let isolate = unsafe { &mut *isolate_ptr };
+  let mut scope = unsafe { v8::CallbackScope::new(isolate) };
+  Ok(serde_v8::Value {
+    v8_value: native_value_to_v8(&mut scope, result),
+  })
+}
+
+/// The result of a native call, kept as plain Rust scalars rather than
+/// `serde_json::Value` until `native_value_to_v8` hands each variant to V8
+/// as the representation that actually fits it — notably `u64`/`i64` as
+/// `BigInt`, never as an f64 `Number`, which would silently truncate past
+/// 2^53. A plain `#[derive(Serialize)]` can't give us that: `serde_v8`
+/// forwards `serialize_u64`/`serialize_i64` straight through to
+/// `serialize_f64`, so a derived impl would truncate exactly the values
+/// this type exists to protect.
+enum NativeValue {
+  Void,
+  U8(u8),
+  I8(i8),
+  U16(u16),
+  I16(i16),
+  U32(u32),
+  I32(i32),
+  U64(u64),
+  I64(i64),
+  USize(usize),
+  ISize(isize),
+  F32(f32),
+  F64(f64),
+  Pointer(u64),
+  CStr(Option<String>),
+  Buffer(Vec<u8>),
+}
+
+/// Invokes a prepared [`Symbol`] and marshals its result into a
+/// [`NativeValue`] for the fast path straight to V8.
+/// Shared by the blocking and non-blocking call ops.
+fn call_symbol(
+  cif: &Cif,
+  ptr: CodePtr,
+  args: &[Arg],
+  result_type: &FFIType,
+  out_buffer: Option<Vec<u8>>,
+) -> Result<NativeValue, AnyError> {
+  Ok(match result_type {
+    FFIType::Void => {
+      unsafe { cif.call::<()>(ptr, args) };
+      NativeValue::Void
+    }
+    FFIType::U8 => NativeValue::U8(unsafe { cif.call::<u8>(ptr, args) }),
+    FFIType::I8 => NativeValue::I8(unsafe { cif.call::<i8>(ptr, args) }),
+    FFIType::U16 => NativeValue::U16(unsafe { cif.call::<u16>(ptr, args) }),
+    FFIType::I16 => NativeValue::I16(unsafe { cif.call::<i16>(ptr, args) }),
+    FFIType::U32 => NativeValue::U32(unsafe { cif.call::<u32>(ptr, args) }),
+    FFIType::I32 => NativeValue::I32(unsafe { cif.call::<i32>(ptr, args) }),
+    FFIType::U64 => NativeValue::U64(unsafe { cif.call::<u64>(ptr, args) }),
+    FFIType::I64 => NativeValue::I64(unsafe { cif.call::<i64>(ptr, args) }),
+    FFIType::USize => {
+      NativeValue::USize(unsafe { cif.call::<usize>(ptr, args) })
+    }
+    FFIType::ISize => {
+      NativeValue::ISize(unsafe { cif.call::<isize>(ptr, args) })
+    }
+    FFIType::F32 => NativeValue::F32(unsafe { cif.call::<f32>(ptr, args) }),
+    FFIType::F64 => NativeValue::F64(unsafe { cif.call::<f64>(ptr, args) }),
+    FFIType::Pointer | FFIType::Buffer | FFIType::Callback(_, _) => {
+      NativeValue::Pointer(
+        unsafe { cif.call::<*mut c_void>(ptr, args) } as u64,
+      )
+    }
+    FFIType::CStr => {
+      let result = unsafe { cif.call::<*const c_char>(ptr, args) };
+      NativeValue::CStr(if result.is_null() {
+        None
+      } else {
+        Some(
+          unsafe { CStr::from_ptr(result) }
+            .to_string_lossy()
+            .into_owned(),
+        )
+      })
+    }
+    // `Cif::call::<T>` needs a concrete, statically-sized `T`, which a
+    // struct return type doesn't have. Drop to the raw libffi API instead,
+    // writing the result into a caller-sized (or freshly allocated) byte
+    // buffer, mirroring `ffi_call_rtype_struct` in the mashin FFI engine.
+    FFIType::Struct(fields) => {
+      let size = ffi_type_size(fields);
+      let mut out_buffer = match out_buffer {
+        Some(out_buffer) if out_buffer.len() < size => {
+          return Err(type_error(format!(
+            "Expected ffi out_buffer to be at least {} bytes, got {}",
+            size,
+            out_buffer.len()
+          )))
+        }
+        Some(out_buffer) => out_buffer,
+        None => vec![0u8; size],
+      };
+      let mut raw_args: Vec<*mut c_void> =
+        args.iter().map(|arg| arg.as_raw_ptr()).collect();
+      unsafe {
+        libffi::raw::ffi_call(
+          cif.as_raw_ptr(),
+          Some(*ptr.as_fun_ptr()),
+          out_buffer.as_mut_ptr() as *mut c_void,
+          raw_args.as_mut_ptr(),
+        );
+      }
+      NativeValue::Buffer(out_buffer)
+    }
   })
 }
+
+/// [`CodePtr`] is just a wrapped raw pointer with no interior mutability,
+/// so it's sound to hand it to the blocking thread pool for the duration
+/// of a single call; this newtype is what makes that `Send` to the
+/// compiler.
+struct SendableCodePtr(CodePtr);
+unsafe impl Send for SendableCodePtr {}
+
+/// Same reasoning as [`SendableCodePtr`], for the prepared [`Arg`]s: each
+/// one is a raw pointer into an `ArgBacking` that is moved into the
+/// blocking closure alongside it, so it remains valid for the call.
+struct SendableArgs(Vec<Arg>);
+unsafe impl Send for SendableArgs {}
+
+/// [`ArgBacking::Pointer`] wraps a raw pointer, which makes `ArgBacking`
+/// (and so `Vec<ArgBacking>`) `!Send` by default even though nothing
+/// about moving it to another thread is actually unsound: the blocking
+/// task owns the backings outright and neither the backings nor the
+/// `Arg`s pointing into them are touched concurrently from the async
+/// task that spawned it. This newtype is what makes that `Send` to the
+/// compiler, the same way `SendableArgs` does for the `Arg`s themselves.
+struct SendableBackings(Vec<ArgBacking>);
+unsafe impl Send for SendableBackings {}
+
+/// Keeps a dylib (and the `Symbol`s resolved into it) alive for the
+/// duration of a blocking call. It stays with the op's own future on the
+/// isolate thread and is never moved into the blocking closure, so the
+/// `Rc`'s non-atomic refcount is only ever touched from that one thread.
+/// Without this, closing the dylib resource
+/// (or dropping its last other handle) while the call is in flight would
+/// `dlclose` out from under code the blocking thread is still executing.
+struct SendableDylibResource(Rc<DylibResource>);
+unsafe impl Send for SendableDylibResource {}
+
+async fn op_dlcall_nonblocking<FP>(
+  state: Rc<RefCell<OpState>>,
+  rid: ResourceId,
+  dlcall_args: DlcallArgs,
+) -> Result<serde_v8::Value, AnyError>
+where
+  FP: FfiPermissions + 'static,
+{
+  let (ptr, parameter_types, result_type, args, backings, dylib, isolate_ptr) = {
+    let mut state = state.borrow_mut();
+    check_unstable(&state, "Deno.dlcall (nonblocking)");
+    let permissions = state.borrow_mut::<FP>();
+    permissions.check()?;
+
+    let resource = state
+      .resource_table
+      .get::<DylibResource>(rid)
+      .ok_or_else(bad_resource_id)?;
+    let symbol = resource.symbols.get(&dlcall_args.symbol).ok_or_else(|| {
+      type_error(format!("Invalid FFI symbol name: {}", dlcall_args.symbol))
+    })?;
+    if !symbol.nonblocking {
+      return Err(type_error(format!(
+        "FFI symbol '{}' was not registered as nonblocking",
+        dlcall_args.symbol
+      )));
+    }
+
+    let (backings, args): (Vec<ArgBacking>, Vec<Arg>) = symbol
+      .parameter_types
+      .iter()
+      .cloned()
+      .zip(dlcall_args.parameters.iter())
+      .map(|(r#type, value)| ffi_arg(r#type, value))
+      .collect::<Result<Vec<_>, AnyError>>()?
+      .into_iter()
+      .unzip();
+
+    (
+      SendableCodePtr(symbol.ptr),
+      symbol.parameter_types.clone(),
+      symbol.result_type.clone(),
+      SendableArgs(args),
+      SendableBackings(backings),
+      SendableDylibResource(resource.clone()),
+      *state.borrow::<*mut v8::Isolate>(),
+    )
+  };
+  let out_buffer = dlcall_args.out_buffer;
+
+  let result = tokio::task::spawn_blocking(move || {
+    // Rebuilt here rather than moved across the thread boundary, since
+    // `Cif` wraps a raw pointer too and there's no need to make it `Send`
+    // when it's this cheap to reconstruct from the owned type lists.
+    let cif = Cif::new(
+      parameter_types.iter().cloned().map(Into::into),
+      result_type.clone().into(),
+    );
+    let SendableArgs(args) = args;
+    // Kept alive until here, for as long as `args` points into it.
+    let SendableBackings(_backings) = backings;
+    call_symbol(&cif, ptr.0, &args, &result_type, out_buffer)
+  })
+  .await??;
+  // Only released now that the call has finished, so the dylib (and the
+  // code `ptr` points into) can't be unmapped by a `close()` racing it.
+  drop(dylib);
+
+  // Back on the isolate's own thread now that the blocking call has
+  // finished, so it's safe to re-enter it the same way
+  // `op_ffi_unsafe_callback_create` does, and convert the result straight
+  // to V8 via `native_value_to_v8` rather than routing it through serde.
+  let isolate = unsafe { &mut *isolate_ptr };
+  let mut scope = unsafe { v8::CallbackScope::new(isolate) };
+  Ok(serde_v8::Value {
+    v8_value: native_value_to_v8(&mut scope, result),
+  })
+}
+
+/// Backing storage for a native function pointer that calls back into JS,
+/// created by `Deno.UnsafeCallback`. Kept alive via the resource table for
+/// as long as native code may still invoke the trampoline through the
+/// pointer handed out at creation time; closing the resource frees it.
+struct CallbackResource {
+  _closure: libffi::middle::Closure<'static>,
+  ptr: CodePtr,
+}
+
+impl Resource for CallbackResource {
+  fn name(&self) -> Cow<str> {
+    "ffiCallback".into()
+  }
+
+  fn close(self: Rc<Self>) {
+    drop(self)
+  }
+}
+
+/// Everything the trampoline needs at invocation time to re-enter the
+/// isolate and call `callback`: the JS function itself, a raw isolate
+/// pointer to build a scope from (like `deno_webgpu`, stashed in `OpState`
+/// at runtime bootstrap since native code may call the trampoline from
+/// outside any op dispatch), and the declared signature used to marshal
+/// arguments and the return value.
+struct CallbackInfo {
+  callback: v8::Global<v8::Function>,
+  isolate: *mut v8::Isolate,
+  parameter_types: Vec<FFIType>,
+  result_type: FFIType,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallbackCreateArgs {
+  parameters: Vec<FFITypeDef>,
+  result: FFITypeDef,
+}
+
+fn op_ffi_unsafe_callback_create<FP>(
+  state: &mut deno_core::OpState,
+  args: CallbackCreateArgs,
+  cb: serde_v8::Value,
+) -> Result<ResourceId, AnyError>
+where
+  FP: FfiPermissions + 'static,
+{
+  check_unstable(state, "Deno.UnsafeCallback");
+  let permissions = state.borrow_mut::<FP>();
+  permissions.check()?;
+
+  let isolate_ptr = *state.borrow::<*mut v8::Isolate>();
+  let callback = {
+    let isolate = unsafe { &mut *isolate_ptr };
+    let mut scope = unsafe { v8::CallbackScope::new(isolate) };
+    let function = v8::Local::<v8::Function>::try_from(cb.v8_value)
+      .map_err(|_| type_error("Expected ffi callback value to be a function"))?;
+    v8::Global::new(&mut scope, function)
+  };
+
+  let parameter_types: Vec<FFIType> = args
+    .parameters
+    .into_iter()
+    .map(FFIType::try_from)
+    .collect::<Result<_, AnyError>>()?;
+  let result_type = FFIType::try_from(args.result)?;
+
+  // Leaked so the `CallbackInfo` stays valid for as long as the closure
+  // that borrows it; the `CallbackResource` drop is what a future libffi
+  // version would ideally reclaim this through, but for now it lives for
+  // the process lifetime once created, matching the "obtain and later
+  // free the pointer" contract at the code-pointer level, not this struct.
+  let info: &'static CallbackInfo = Box::leak(Box::new(CallbackInfo {
+    callback,
+    isolate: isolate_ptr,
+    parameter_types: parameter_types.clone(),
+    result_type: result_type.clone(),
+  }));
+
+  let cif = Cif::new(
+    parameter_types.iter().cloned().map(Into::into),
+    result_type.into(),
+  );
+  let closure =
+    libffi::middle::Closure::new(cif, ffi_callback_trampoline, info);
+  let ptr = *closure.code_ptr();
+
+  Ok(
+    state
+      .resource_table
+      .add(CallbackResource { _closure: closure, ptr }),
+  )
+}
+
+/// Reads back the trampoline's code pointer for a previously created
+/// `CallbackResource`, so it can be passed as a `"pointer"`/`"callback"`
+/// typed `Deno.dlcall()` argument.
+fn op_ffi_unsafe_callback_ref(
+  state: &mut deno_core::OpState,
+  rid: ResourceId,
+  _: (),
+) -> Result<u64, AnyError> {
+  let resource = state
+    .resource_table
+    .get::<CallbackResource>(rid)
+    .ok_or_else(bad_resource_id)?;
+  Ok(resource.ptr.as_ptr() as u64)
+}
+
+/// Re-enters the JS isolate to run the stored JS function, converting the
+/// incoming native arguments to JS values per the declared signature and
+/// marshalling the JS return value back to the native return type.
+extern "C" fn ffi_callback_trampoline(
+  cif: &libffi::low::ffi_cif,
+  result: &mut u64,
+  args: &[*const c_void],
+  info: &CallbackInfo,
+) {
+  let _ = cif;
+  let isolate = unsafe { &mut *info.isolate };
+  let mut callback_scope = unsafe { v8::CallbackScope::new(isolate) };
+  let scope = &mut v8::HandleScope::new(&mut callback_scope);
+  let context = scope.get_current_context();
+  let scope = &mut v8::ContextScope::new(scope, context);
+
+  let callback = v8::Local::new(scope, &info.callback);
+  let this = v8::undefined(scope).into();
+
+  let js_args: Vec<v8::Local<v8::Value>> = info
+    .parameter_types
+    .iter()
+    .zip(args.iter())
+    .map(|(r#type, arg)| native_arg_to_v8(scope, r#type, *arg))
+    .collect();
+
+  if let Some(return_value) = callback.call(scope, this, &js_args) {
+    *result = v8_to_native_result(scope, &info.result_type, return_value);
+  }
+}
+
+fn native_arg_to_v8<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  r#type: &FFIType,
+  arg: *const c_void,
+) -> v8::Local<'s, v8::Value> {
+  match r#type {
+    FFIType::Void => v8::undefined(scope).into(),
+    FFIType::F32 => {
+      v8::Number::new(scope, unsafe { *(arg as *const f32) } as f64).into()
+    }
+    FFIType::F64 => v8::Number::new(scope, unsafe { *(arg as *const f64) }).into(),
+    FFIType::U64 => {
+      v8::BigInt::new_from_u64(scope, unsafe { *(arg as *const u64) }).into()
+    }
+    FFIType::I64 => {
+      v8::BigInt::new_from_i64(scope, unsafe { *(arg as *const i64) }).into()
+    }
+    // `usize`/`isize` are pointer-width, same as `u64`/`i64` on every
+    // target this runs on — reading them as the 4-byte catch-all below
+    // would silently drop the high bytes of anything that doesn't fit in
+    // 32 bits.
+    FFIType::USize => {
+      v8::BigInt::new_from_u64(scope, unsafe { *(arg as *const usize) } as u64)
+        .into()
+    }
+    FFIType::ISize => {
+      v8::BigInt::new_from_i64(scope, unsafe { *(arg as *const isize) } as i64)
+        .into()
+    }
+    FFIType::Pointer | FFIType::Buffer | FFIType::CStr | FFIType::Callback(_, _) => {
+      v8::BigInt::new_from_u64(scope, unsafe { *(arg as *const *const c_void) } as u64)
+        .into()
+    }
+    // The remaining scalar integer types all fit losslessly in an f64.
+    FFIType::U8 => v8::Number::new(scope, unsafe { *(arg as *const u8) } as f64).into(),
+    FFIType::I8 => v8::Number::new(scope, unsafe { *(arg as *const i8) } as f64).into(),
+    FFIType::U16 => v8::Number::new(scope, unsafe { *(arg as *const u16) } as f64).into(),
+    FFIType::I16 => v8::Number::new(scope, unsafe { *(arg as *const i16) } as f64).into(),
+    FFIType::U32 => v8::Number::new(scope, unsafe { *(arg as *const u32) } as f64).into(),
+    _ => v8::Number::new(scope, unsafe { *(arg as *const i32) } as f64).into(),
+  }
+}
+
+/// Converts a [`NativeValue`] — the plain-Rust-scalar result of a native
+/// call — into the V8 value `Deno.dlcall()` actually returns, the same way
+/// [`native_arg_to_v8`] does for callback arguments: `u64`/`i64` (and the
+/// pointer-shaped variants) become `BigInt`s rather than `Number`s, so a
+/// large native result doesn't silently lose precision past 2^53.
+fn native_value_to_v8<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  value: NativeValue,
+) -> v8::Local<'s, v8::Value> {
+  match value {
+    NativeValue::Void => v8::undefined(scope).into(),
+    NativeValue::U8(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::I8(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::U16(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::I16(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::U32(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::I32(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::F32(v) => v8::Number::new(scope, v as f64).into(),
+    NativeValue::F64(v) => v8::Number::new(scope, v).into(),
+    NativeValue::U64(v) => v8::BigInt::new_from_u64(scope, v).into(),
+    NativeValue::I64(v) => v8::BigInt::new_from_i64(scope, v).into(),
+    NativeValue::USize(v) => v8::BigInt::new_from_u64(scope, v as u64).into(),
+    NativeValue::ISize(v) => v8::BigInt::new_from_i64(scope, v as i64).into(),
+    NativeValue::Pointer(v) => v8::BigInt::new_from_u64(scope, v).into(),
+    NativeValue::CStr(None) => v8::null(scope).into(),
+    NativeValue::CStr(Some(s)) => v8::String::new(scope, &s)
+      .expect("Expected native CStr result to fit in a v8::String")
+      .into(),
+    NativeValue::Buffer(bytes) => {
+      let len = bytes.len();
+      let ab = v8::ArrayBuffer::new(scope, len);
+      if let Some(data) = ab.get_backing_store().data() {
+        unsafe {
+          std::ptr::copy_nonoverlapping(
+            bytes.as_ptr(),
+            data.as_ptr() as *mut u8,
+            len,
+          );
+        }
+      }
+      v8::Uint8Array::new(scope, ab, 0, len)
+        .expect("Expected native struct result to fit in a Uint8Array")
+        .into()
+    }
+  }
+}
+
+fn v8_to_native_result(
+  scope: &mut v8::HandleScope,
+  r#type: &FFIType,
+  value: v8::Local<v8::Value>,
+) -> u64 {
+  match r#type {
+    FFIType::Void => 0,
+    FFIType::F64 => value.number_value(scope).unwrap_or_default().to_bits(),
+    // The result slot is a u64 regardless of the native return type, so an
+    // f32 result has to be packed into the low 4 bytes of it: widening its
+    // bit pattern as if it were an f64 would write the wrong bytes for
+    // whatever reads this back out as an f32.
+    FFIType::F32 => {
+      (value.number_value(scope).unwrap_or_default() as f32).to_bits() as u64
+    }
+    // `usize`/`isize` are pointer-width, same as `u64`/`i64` — routing
+    // them through the `integer_value` fallback below would round-trip
+    // through an f64-ish path and silently lose precision above 2^53, the
+    // same truncation `native_value_to_v8` avoids for `Deno.dlcall()`
+    // results by going through `BigInt` instead.
+    FFIType::U64
+    | FFIType::I64
+    | FFIType::USize
+    | FFIType::ISize
+    | FFIType::Pointer
+    | FFIType::Buffer => value
+      .to_big_int(scope)
+      .map(|b| b.u64_value().0)
+      .unwrap_or_default(),
+    _ => value.integer_value(scope).unwrap_or_default() as u64,
+  }
+}